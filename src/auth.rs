@@ -0,0 +1,53 @@
+//! Credential store for restricting who may authenticate via SMTP AUTH (PLAIN/LOGIN).
+//!
+//! Without a configured [`CredentialStore`] the receiver stays an open relay (unchanged, opt-in
+//! behaviour); with one, `MailHandler::mail` refuses MAIL until a matching username/password has
+//! been presented, so arbitrary third parties can't inject fake gov.uk change emails. This
+//! applies to the SMTP listener only: LMTP sessions are never given a credential store and
+//! accept MAIL without AUTH either way.
+
+use anyhow::{Context, Result};
+use argon2::{
+    password_hash::{PasswordHash, PasswordVerifier},
+    Argon2,
+};
+use std::{collections::HashMap, fs, path::Path};
+
+/// Username -> argon2 PHC hash of the password, loaded from a `username:phc_hash` file (one
+/// pair per line, blank lines and `#`-prefixed comments ignored).
+pub struct CredentialStore {
+    users: HashMap<String, String>,
+}
+
+impl CredentialStore {
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self> {
+        let contents = fs::read_to_string(path.as_ref()).context("Reading SMTP AUTH credentials file")?;
+        let mut users = HashMap::new();
+        for line in contents.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (username, hash) = line
+                .split_once(':')
+                .with_context(|| format!("Malformed credentials line (expected \"username:phc_hash\"): {}", line))?;
+            users.insert(username.to_owned(), hash.to_owned());
+        }
+        Ok(CredentialStore { users })
+    }
+
+    /// Verifies `password` for `username` against the stored argon2 hash, failing closed
+    /// (`false`) on an unknown username or a malformed/mismatched hash rather than surfacing an
+    /// error to the SMTP client.
+    pub fn verify(&self, username: &str, password: &str) -> bool {
+        self.users
+            .get(username)
+            .and_then(|hash| PasswordHash::new(hash).ok())
+            .map(|parsed_hash| {
+                Argon2::default()
+                    .verify_password(password.as_bytes(), &parsed_hash)
+                    .is_ok()
+            })
+            .unwrap_or(false)
+    }
+}
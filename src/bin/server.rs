@@ -1,22 +1,68 @@
 use anyhow::{bail, Context, Result};
-use chrono::{SecondsFormat, Utc};
 use dotenv::dotenv;
-use file_lock::FileLock;
 use git2::{Commit, Repository, Signature};
-use gitgov_rs::{email_update::GovUkChange, git::CommitBuilder, retrieve_doc};
+use gitgov_rs::{
+    auth::CredentialStore,
+    doc::diff_summary,
+    email_update::GovUkChange,
+    filter,
+    filter::RuleSet,
+    git::CommitBuilder,
+    ingest::{ImapSource, IngestSource},
+    ledger::Ledger,
+    mail_store::{CustomLayoutStore, MailStore, MaildirStore, MemFdStagingStore, PendingMessage},
+    retrieve_doc,
+    retry::{with_backoff, Failure, IsOnline, DEFAULT_MAX_ATTEMPTS},
+    Doc,
+};
+use rustls::{ServerConfig, ServerConnection, StreamOwned};
 use std::{
-    collections::VecDeque,
+    collections::HashSet,
     fs,
     io::{self, BufRead, BufReader, Read, Write},
     net::{SocketAddr, TcpListener, TcpStream},
     path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    thread,
+    time::Duration,
 };
 use url::Url;
 
 struct MailHandler {
     peer_addr: SocketAddr,
-    inbox: PathBuf,
-    data: Option<EmailWrite>,
+    store: Arc<dyn MailStore>,
+    filter: Option<Arc<RuleSet>>,
+    from: String,
+    to: Vec<String>,
+    /// Buffers the whole message so filter rules (which can match on the `Subject:` header,
+    /// only available once DATA is fully received) get to decide whether/where to store it
+    /// before anything touches disk.
+    buffer: Vec<u8>,
+    credentials: Option<Arc<CredentialStore>>,
+    authenticated: bool,
+    /// The recipient list gathered in `data_start`, shared with the session loop so it can emit
+    /// one DATA_END reply per recipient in LMTP mode.
+    recipients: Arc<Mutex<Vec<String>>>,
+}
+
+impl MailHandler {
+    /// Verifies `username`/`password` against `self.credentials`, or succeeds unconditionally
+    /// when no credential store is configured (the open-relay default).
+    fn try_authenticate(&mut self, username: &str, password: &str) -> mailin::Response {
+        let verified = self
+            .credentials
+            .as_ref()
+            .map(|credentials| credentials.verify(username, password))
+            .unwrap_or(true);
+        if verified {
+            println!("{}: AUTH succeeded for {}", self.peer_addr, username);
+            self.authenticated = true;
+            mailin::response::OK
+        } else {
+            println!("{}: AUTH failed for {}", self.peer_addr, username);
+            mailin::response::INVALID_CREDENTIALS
+        }
+    }
 }
 
 impl mailin::Handler for MailHandler {
@@ -25,8 +71,20 @@ impl mailin::Handler for MailHandler {
         mailin::response::OK
     }
 
+    fn auth_plain(&mut self, _authorization_id: &str, authentication_id: &str, password: &str) -> mailin::Response {
+        self.try_authenticate(authentication_id, password)
+    }
+
+    fn auth_login(&mut self, authentication_id: &str, password: &str) -> mailin::Response {
+        self.try_authenticate(authentication_id, password)
+    }
+
     fn mail(&mut self, ip: std::net::IpAddr, domain: &str, from: &str) -> mailin::Response {
         println!("{}: MAIL {}", self.peer_addr, from);
+        if !self.authenticated {
+            println!("{}: Rejecting MAIL from {} - AUTH required but not completed", self.peer_addr, from);
+            return mailin::response::NO_SERVICE;
+        }
         let from_match = dotenv::var("FROM_FILTER")
             .ok()
             .map(|from_filter| from.contains(&from_filter));
@@ -47,99 +105,96 @@ impl mailin::Handler for MailHandler {
     }
 
     fn data_start(&mut self, _domain: &str, from: &str, _is8bit: bool, to: &[String]) -> mailin::Response {
-        let email_path = inbox_path_for_email(&self.inbox, from, to);
-        match EmailWrite::create(email_path) {
-            Ok(writer) => {
-                println!(
-                    "{}: Writing email to {}",
-                    self.peer_addr,
-                    writer.path.to_str().unwrap_or_default()
-                );
-                self.data = Some(writer);
-                mailin::response::OK
-            }
-            Err(err) => {
-                println!("{}: Error mapping email envelope to inbox : {}", self.peer_addr, err);
-                mailin::response::INTERNAL_ERROR
-            }
-        }
+        *self.recipients.lock().unwrap() = to.to_vec();
+        println!("{}: Buffering email from {} to {:?}", self.peer_addr, from, to);
+        self.from = from.to_owned();
+        self.to = to.to_vec();
+        self.buffer.clear();
+        mailin::response::OK
     }
 
     fn data(&mut self, buf: &[u8]) -> io::Result<()> {
-        if let Some(writer) = &mut self.data {
-            writer.write_all(buf)
-        } else {
-            Err(io::ErrorKind::NotConnected.into())
-        }
+        self.buffer.extend_from_slice(buf);
+        Ok(())
     }
 
     fn data_end(&mut self) -> mailin::Response {
-        if let Some(mut writer) = self.data.take() {
-            match writer.flush() {
-                Ok(()) => mailin::response::OK,
-                Err(err) => {
-                    println!("Error flushing : {}", err);
-                    mailin::response::INTERNAL_ERROR
-                }
-            }
-        } else {
-            mailin::response::INTERNAL_ERROR
-        }
-    }
-}
-
-struct EmailWrite {
-    path: PathBuf,
-    lock: FileLock,
-}
-
-impl EmailWrite {
-    fn create(path: PathBuf) -> Result<Self> {
-        fs::create_dir_all(path.parent().unwrap())?;
-        Ok(EmailWrite {
-            lock: FileLock::lock(&path.to_str().unwrap(), true, true)?,
-            path,
-        })
-    }
-}
+        let subject = filter::extract_subject(&self.buffer);
+        let message = filter::MessageInfo {
+            from: &self.from,
+            to: &self.to,
+            subject,
+        };
+        let action = self.filter.as_ref().and_then(|rules| rules.evaluate(&message));
 
-impl Write for EmailWrite {
-    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
-        self.lock.file.write(buf)
-    }
+        if action == Some(filter::Action::Discard) {
+            println!("{}: Discarding message from {} per filter rule", self.peer_addr, self.from);
+            return mailin::response::OK;
+        }
+        let routed_to = match action {
+            Some(filter::Action::RouteTo(name)) => vec![name],
+            _ => self.to.clone(),
+        };
 
-    fn flush(&mut self) -> std::io::Result<()> {
-        self.lock.file.flush()
+        let mut writer = match self.store.create_writer(&self.from, &routed_to) {
+            Ok(writer) => writer,
+            Err(err) => {
+                println!("{}: Error opening a writer in the mail store : {}", self.peer_addr, err);
+                return mailin::response::INTERNAL_ERROR;
+            }
+        };
+        if let Err(err) = writer.write_all(&self.buffer) {
+            println!("{}: Error writing buffered message to the mail store : {}", self.peer_addr, err);
+            return mailin::response::INTERNAL_ERROR;
+        }
+        match writer.finish() {
+            Ok(()) => mailin::response::OK,
+            Err(err) => {
+                println!("Error finishing message : {}", err);
+                mailin::response::INTERNAL_ERROR
+            }
+        }
     }
 }
 
-impl Drop for EmailWrite {
-    fn drop(&mut self) {
-        println!("Finished writing {}", self.path.to_string_lossy());
-    }
-}
+/// Loads a TLS server config from the certificate/key pair named by `TLS_CERT`/`TLS_KEY`.
+/// Returns `None` (and leaves STARTTLS unadvertised) when those env vars aren't set.
+fn load_tls_config() -> Result<Option<Arc<ServerConfig>>> {
+    let (cert_path, key_path) = match (dotenv::var("TLS_CERT"), dotenv::var("TLS_KEY")) {
+        (Ok(cert), Ok(key)) => (cert, key),
+        _ => return Ok(None),
+    };
 
-fn inbox_path_for_email(inbox: &PathBuf, from: &str, to: &[String]) -> PathBuf {
-    let from_domain = from.split('@').nth(1);
-    inbox
-        .join(from_domain.unwrap_or(from))
-        .join(to.join(","))
-        .join(Utc::now().to_rfc3339_opts(SecondsFormat::AutoSi, true))
-        .with_extension("eml")
+    let certs = rustls_pemfile::certs(&mut BufReader::new(
+        fs::File::open(&cert_path).context("Opening TLS_CERT")?,
+    ))
+    .collect::<Result<Vec<_>, _>>()
+    .context("Parsing TLS_CERT")?;
+    let key = rustls_pemfile::private_key(&mut BufReader::new(
+        fs::File::open(&key_path).context("Opening TLS_KEY")?,
+    ))
+    .context("Parsing TLS_KEY")?
+    .context("No private key found in TLS_KEY")?;
+
+    let config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .context("Building TLS server config")?;
+    Ok(Some(Arc::new(config)))
 }
 
-/// accepts emails from gov.uk and saves them in `inbox/{from}/{to}/{datetime}.eml
-fn receive_updates_on_socket(mut stream: TcpStream, remote_addr: SocketAddr, inbox: impl AsRef<Path>) -> Result<()> {
-    let peer_addr = stream.peer_addr()?;
-    let handler = MailHandler {
-        peer_addr,
-        inbox: inbox.as_ref().to_path_buf(),
-        data: None,
-    };
-    let mut session = mailin::SessionBuilder::new("gitgov").build(remote_addr.ip(), handler);
-    session.greeting().write_to(&mut stream)?;
-
-    let mut buf_read = BufReader::new(stream.try_clone()?);
+/// Runs the SMTP/LMTP command loop against `stream` until the peer disconnects or requests
+/// STARTTLS. Returns the unwrapped stream so the caller can upgrade it and keep looping on the
+/// same session. In LMTP mode (`lmtp_recipients` is `Some`), the DATA terminator ("." on its own
+/// line) gets one reply per gathered recipient instead of the single SMTP-style reply, per the
+/// LMTP spec (RFC 2033).
+fn run_session<S: Read + Write>(
+    session: &mut mailin::Session<MailHandler>,
+    stream: S,
+    peer_addr: SocketAddr,
+    lmtp_recipients: Option<Arc<Mutex<Vec<String>>>>,
+) -> Result<(bool, S)> {
+    let mut buf_read = BufReader::new(stream);
     let mut command = String::new();
 
     loop {
@@ -154,53 +209,274 @@ fn receive_updates_on_socket(mut stream: TcpStream, remote_addr: SocketAddr, inb
         match result.action {
             mailin::Action::Close => {
                 println!("{}: CLOSE", peer_addr);
-                result.write_to(&mut stream)?;
+                result.write_to(buf_read.get_mut())?;
                 break;
             }
-            mailin::Action::UpgradeTls => bail!("TLS requested"),
+            mailin::Action::UpgradeTls => {
+                // the "220 Ready to start TLS" reply has to reach the client before the handshake
+                result.write_to(buf_read.get_mut())?;
+                return Ok((true, buf_read.into_inner()));
+            }
             mailin::Action::NoReply => continue,
-            mailin::Action::Reply => result.write_to(&mut stream).context(format!(
-                "{}: Writing SMTP reply failed when responding to '{}' with '{:?}'",
-                peer_addr, command, result
-            ))?,
+            mailin::Action::Reply => {
+                let is_data_end = command.trim_end_matches(|c: char| c == '\r' || c == '\n') == ".";
+                match &lmtp_recipients {
+                    // `recipients` is gathered in `data_start` and can be empty if the client
+                    // never sent an RCPT before DATA; falling through to the single-reply arm
+                    // there keeps at least one reply flowing instead of silently hanging the
+                    // client, which would otherwise wait forever for a DATA_END response.
+                    Some(recipients) if is_data_end && !recipients.lock().unwrap().is_empty() => {
+                        for to in recipients.lock().unwrap().iter() {
+                            result.write_to(buf_read.get_mut()).context(format!(
+                                "{}: Writing LMTP DATA_END reply for recipient {} failed",
+                                peer_addr, to
+                            ))?;
+                        }
+                    }
+                    _ => result.write_to(buf_read.get_mut()).context(format!(
+                        "{}: Writing reply failed when responding to '{}' with '{:?}'",
+                        peer_addr, command, result
+                    ))?,
+                }
+            }
         }
     }
+    Ok((false, buf_read.into_inner()))
+}
+
+/// accepts emails from gov.uk and saves them into `store`, upgrading to TLS on STARTTLS when
+/// `tls_config` is configured, requiring SMTP AUTH when `credentials` is configured, and
+/// switching to LMTP's LHLO greeting and per-recipient DATA_END replies when `lmtp` is set
+fn receive_updates_on_socket(
+    mut stream: TcpStream,
+    remote_addr: SocketAddr,
+    store: Arc<dyn MailStore>,
+    tls_config: Option<&Arc<ServerConfig>>,
+    credentials: Option<Arc<CredentialStore>>,
+    filter: Option<Arc<RuleSet>>,
+    lmtp: bool,
+) -> Result<()> {
+    let peer_addr = stream.peer_addr()?;
+    stream.set_read_timeout(Some(Duration::from_secs(5 * 60)))?;
+
+    let recipients = Arc::new(Mutex::new(Vec::new()));
+    // AUTH is only ever enabled on the session built for the encrypted side of the connection
+    // (`tls_active: true` below) - credentials submitted over AUTH PLAIN/LOGIN are plain base64,
+    // so advertising and accepting AUTH before STARTTLS would let a client hand over a password
+    // in the clear and defeat the point of checking it against the argon2 store at all.
+    let build_session = |tls_active: bool| {
+        let handler = MailHandler {
+            peer_addr,
+            store: store.clone(),
+            filter: filter.clone(),
+            from: String::new(),
+            to: vec![],
+            buffer: vec![],
+            credentials: credentials.clone(),
+            authenticated: credentials.is_none(),
+            recipients: recipients.clone(),
+        };
+        let mut session_builder = mailin::SessionBuilder::new("gitgov");
+        if tls_config.is_some() {
+            session_builder.enable_start_tls();
+        }
+        if credentials.is_some() && tls_active {
+            session_builder.enable_auth(vec![mailin::AuthMechanism::Plain, mailin::AuthMechanism::Login]);
+        }
+        if lmtp {
+            session_builder.enable_lmtp();
+        }
+        session_builder.build(remote_addr.ip(), handler)
+    };
+
+    let mut session = build_session(false);
+    session.greeting().write_to(&mut stream)?;
+
+    let lmtp_recipients = lmtp.then_some(recipients);
+    let (tls_requested, stream) = run_session(&mut session, stream, peer_addr, lmtp_recipients.clone())?;
+    if !tls_requested {
+        return Ok(());
+    }
+
+    let tls_config = tls_config.context("STARTTLS requested but no TLS_CERT/TLS_KEY configured")?;
+    let connection = ServerConnection::new(tls_config.clone()).context("Starting TLS handshake")?;
+    let tls_stream = StreamOwned::new(connection, stream);
+    // Rebuilt with AUTH enabled now that the transport is encrypted; the client re-issues
+    // EHLO per RFC 3207 and gets this session's (now AUTH-advertising) capabilities.
+    let mut session = build_session(true);
+    run_session(&mut session, tls_stream, peer_addr, lmtp_recipients)?;
+    Ok(())
+}
+
+/// The default [`IngestSource`]: listens on port 25 and accepts one SMTP connection per call.
+struct SmtpSource {
+    socket: TcpListener,
+    store: Arc<dyn MailStore>,
+    tls_config: Option<Arc<ServerConfig>>,
+    credentials: Option<Arc<CredentialStore>>,
+    filter: Option<Arc<RuleSet>>,
+}
+
+impl IngestSource for SmtpSource {
+    fn ingest(&mut self) -> Result<()> {
+        let (stream, remote_addr) = self.socket.accept()?;
+        if let Err(err) = receive_updates_on_socket(
+            stream,
+            remote_addr,
+            self.store.clone(),
+            self.tls_config.as_ref(),
+            self.credentials.clone(),
+            self.filter.clone(),
+            false,
+        ) {
+            println!("Closed SMTP session due to error : {}", err);
+        }
+        Ok(())
+    }
+}
+
+/// Loads the SASL credential store from the file named by `SMTP_AUTH_CREDENTIALS`. Returns
+/// `None` (and leaves the server an open relay) when that env var isn't set.
+fn load_credentials() -> Result<Option<Arc<CredentialStore>>> {
+    match dotenv::var("SMTP_AUTH_CREDENTIALS") {
+        Ok(path) => Ok(Some(Arc::new(
+            CredentialStore::from_file(path).context("Loading SMTP_AUTH_CREDENTIALS")?,
+        ))),
+        Err(_) => Ok(None),
+    }
+}
+
+/// Loads the sieve-style routing/discard rules from the file named by `FILTER_RULES`. Returns
+/// `None` (every message kept, filed under the envelope recipient) when that env var isn't set.
+fn load_filter_rules() -> Result<Option<Arc<RuleSet>>> {
+    match dotenv::var("FILTER_RULES") {
+        Ok(path) => Ok(Some(Arc::new(RuleSet::from_file(path).context("Loading FILTER_RULES")?))),
+        Err(_) => Ok(None),
+    }
+}
+
+/// Runs a dedicated LMTP accept loop on `bind_addr` alongside the main SMTP ingest loop, for
+/// local MTAs (postfix/exim) configured to hand final delivery off via LMTP rather than SMTP.
+/// Each connection gets its own thread since it isn't paced by the outer ingest/process loop.
+fn run_lmtp_listener(bind_addr: &str, store: Arc<dyn MailStore>, filter: Option<Arc<RuleSet>>) -> Result<()> {
+    let socket = TcpListener::bind(bind_addr).context("Binding LMTP listener")?;
+    println!("LMTP listener bound on {}", bind_addr);
+    for conn in socket.incoming() {
+        let stream = conn.context("Accepting LMTP connection")?;
+        let remote_addr = stream.peer_addr()?;
+        let store = store.clone();
+        let filter = filter.clone();
+        thread::spawn(move || {
+            if let Err(err) = receive_updates_on_socket(stream, remote_addr, store, None, None, filter, true) {
+                println!("Closed LMTP session due to error : {}", err);
+            }
+        });
+    }
     Ok(())
 }
 
 fn main() -> Result<()> {
     dotenv()?;
-    const EMAILS_FROM_GOVUK_PATH: &str = "inbox/mail.notifications.service.gov.uk";
+    // The single root incoming mail lands under - `CustomLayoutStore` nests `{from-domain}/{to}/...`
+    // underneath it itself, so this must stay a bare root rather than something already
+    // domain-qualified, or messages end up double-nested under the domain twice.
+    const INBOX_DIR: &str = "inbox";
     const ARCHIVE_DIR: &str = "outbox";
     let repo_path = dotenv::var("REPO")?;
     let reference = dotenv::var("REF")?;
-    fs::create_dir_all(EMAILS_FROM_GOVUK_PATH)
-        .context(format!("Error trying to create dir {}", EMAILS_FROM_GOVUK_PATH))?;
     fs::create_dir_all(ARCHIVE_DIR).context(format!("Error trying to create dir {}", ARCHIVE_DIR))?;
 
+    let online = IsOnline::new();
+
     if dotenv::var("DISABLE_PROCESS_UPDATES").is_err() {
-        push(&repo_path)?;
+        push(&repo_path, &online)?;
+    }
+
+    // STORE_BACKEND=maildir points the dumper at a standard Maildir (for operators who want
+    // other Maildir-reading tools sharing the same inbox); anything else keeps the original
+    // bespoke layout.
+    let mut store: Arc<dyn MailStore> = match dotenv::var("STORE_BACKEND").as_deref() {
+        Ok("maildir") => {
+            println!("STORE_BACKEND=maildir, storing incoming mail in a Maildir under {}", INBOX_DIR);
+            Arc::new(MaildirStore::new(INBOX_DIR, ARCHIVE_DIR).context("Initializing maildir store")?)
+        }
+        _ => {
+            fs::create_dir_all(INBOX_DIR).context(format!("Error trying to create dir {}", INBOX_DIR))?;
+            Arc::new(CustomLayoutStore::new(INBOX_DIR, ARCHIVE_DIR))
+        }
+    };
+    if dotenv::var("MEMFD_SPOOL").is_ok() {
+        println!("MEMFD_SPOOL set, buffering incoming DATA in memfds instead of the inbox directory");
+        store = Arc::new(MemFdStagingStore::new(store));
     }
 
-    let socket = TcpListener::bind("0.0.0.0:25")?;
+    let tls_config = load_tls_config()?;
+    if tls_config.is_none() {
+        println!("TLS_CERT/TLS_KEY not set, STARTTLS will not be advertised");
+    }
+    let credentials = load_credentials().context("Loading SMTP AUTH credentials")?;
+    if credentials.is_none() {
+        println!("SMTP_AUTH_CREDENTIALS not set, the SMTP receiver will accept mail from anyone");
+    }
+    if credentials.is_some() && tls_config.is_none() {
+        // AUTH is only ever enabled on the post-STARTTLS session (see `build_session`), so
+        // without TLS there's no session to advertise/accept it on; `authenticated` would stay
+        // false forever and every MAIL would be rejected with no indication why.
+        bail!("SMTP_AUTH_CREDENTIALS is set but TLS_CERT/TLS_KEY is not - AUTH can never succeed without STARTTLS, so the server would silently refuse all mail");
+    }
+    let filter = load_filter_rules().context("Loading filter rules")?;
+
+    if let Ok(lmtp_bind) = dotenv::var("LMTP_LISTEN") {
+        let lmtp_store = store.clone();
+        let lmtp_filter = filter.clone();
+        thread::spawn(move || {
+            if let Err(err) = run_lmtp_listener(&lmtp_bind, lmtp_store, lmtp_filter) {
+                println!("LMTP listener failed : {}", err);
+            }
+        });
+    }
+
+    let mut source: Box<dyn IngestSource> = match dotenv::var("INGEST_MODE").as_deref() {
+        Ok("imap") => Box::new(ImapSource::from_env(store.clone()).context("Configuring IMAP ingest source")?),
+        _ => Box::new(SmtpSource {
+            socket: TcpListener::bind("0.0.0.0:25")?,
+            store: store.clone(),
+            tls_config,
+            credentials,
+            filter,
+        }),
+    };
+
+    let ledger_path = dotenv::var("LEDGER_DB").unwrap_or_else(|_| "ledger.sqlite3".to_owned());
+    let ledger = Ledger::open(&ledger_path).context("Opening processing ledger")?;
+
     loop {
+        if !online.get() {
+            // already backed off inside with_backoff on the last failure; give the network a
+            // moment before trying to accept/push again rather than spinning immediately
+            std::thread::sleep(std::time::Duration::from_secs(1));
+        }
+
         if dotenv::var("DISABLE_PROCESS_UPDATES").is_err() {
-            let count = process_updates_in_dir(EMAILS_FROM_GOVUK_PATH, ARCHIVE_DIR, &repo_path, &reference)
+            let count = process_updates_in_dir(store.as_ref(), &repo_path, &reference, &ledger)
                 .expect("the processing fails, the repo may be unclean");
             if count > 0 {
                 println!("Processed {} update emails, pushing", count);
-                push(&repo_path).unwrap_or_else(|err| println!("Push failed : {}", err));
+                push(&repo_path, &online).unwrap_or_else(|err| println!("Push failed : {}", err));
             }
         }
 
-        let (stream, remote_addr) = socket.accept()?;
-        if let Err(err) = receive_updates_on_socket(stream, remote_addr, "inbox") {
-            println!("Closed SMTP session due to error : {}", err);
+        if let Err(err) = source.ingest() {
+            println!("Ingest failed : {}", err);
         }
     }
 }
 
-fn push(repo_base: impl AsRef<Path>) -> Result<()> {
+fn push(repo_base: impl AsRef<Path>, online: &IsOnline) -> Result<()> {
+    with_backoff(online, DEFAULT_MAX_ATTEMPTS, || push_once(repo_base.as_ref()))
+}
+
+fn push_once(repo_base: &Path) -> Result<(), Failure> {
     let mut remote_callbacks = git2::RemoteCallbacks::new();
     remote_callbacks.credentials(|_url, username_from_url, _allowed_types| {
         git2::Cred::ssh_key(
@@ -210,125 +486,229 @@ fn push(repo_base: impl AsRef<Path>) -> Result<()> {
             None,
         )
     });
-    let repo = Repository::open(repo_base).context("Opening repo")?;
-    let mut remote = repo.find_remote("origin")?;
-    remote.push(
-        &["refs/heads/main"],
-        Some(git2::PushOptions::new().remote_callbacks(remote_callbacks)),
-    )?;
+    let repo = Repository::open(repo_base)
+        .context("Opening repo")
+        .map_err(Failure::Permanent)?;
+    let mut remote = repo.find_remote("origin").map_err(classify_git_error)?;
+    remote
+        .push(
+            &["refs/heads/main"],
+            Some(git2::PushOptions::new().remote_callbacks(remote_callbacks)),
+        )
+        .map_err(classify_git_error)?;
     Ok(())
 }
 
+/// Network-ish failures (connection/transport errors) are worth retrying; everything else
+/// (bad refspec, auth rejected, ...) won't be fixed by trying again.
+fn classify_git_error(err: git2::Error) -> Failure {
+    if err.code() == git2::ErrorCode::Auth {
+        return Failure::Permanent(anyhow::Error::from(err));
+    }
+    match err.class() {
+        git2::ErrorClass::Net | git2::ErrorClass::Os | git2::ErrorClass::Ssh => {
+            Failure::Transient(anyhow::Error::from(err))
+        }
+        _ => Failure::Permanent(anyhow::Error::from(err)),
+    }
+}
+
 fn process_updates_in_dir(
-    in_dir: impl AsRef<Path>,
-    out_dir: impl AsRef<Path>,
+    store: &dyn MailStore,
     repo: impl AsRef<Path>,
     reference: &str,
+    ledger: &Ledger,
 ) -> Result<u32> {
     let mut count = 0;
-    for to_inbox in fs::read_dir(in_dir)? {
-        let to_inbox = to_inbox?;
-        if to_inbox.metadata()?.is_dir() {
-            for email in fs::read_dir(to_inbox.path())? {
-                let email = email?;
-                process_email_update_file(to_inbox.file_name(), &email, &out_dir, &repo, reference).context(
-                    format!("Failed processing {}", email.path().to_str().unwrap_or_default()),
-                )?;
-                count += 1;
-            }
-        }
+    for message in store.pending()? {
+        let path = message.path.clone();
+        process_email_update_file(store, &message, &repo, reference, ledger)
+            .context(format!("Failed processing {}", path.to_str().unwrap_or_default()))?;
+        count += 1;
     }
     Ok(count)
 }
 
 fn process_email_update_file(
-    to_dir_name: impl AsRef<Path>,
-    dir_entry: &fs::DirEntry,
-    out_dir: impl AsRef<Path>,
+    store: &dyn MailStore,
+    message: &PendingMessage,
     repo_base: impl AsRef<Path>,
     reference: &str,
+    ledger: &Ledger,
 ) -> Result<()> {
-    let data = {
-        let mut lock = FileLock::lock(dir_entry.path().to_str().context("error")?, true, false)
-            .context("Locking file email file")?;
-        let mut bytes = Vec::with_capacity(lock.file.metadata().map(|m| m.len() as usize + 1).unwrap_or(0));
-        lock.file.read_to_end(&mut bytes).context("Reading email file")?;
-        bytes
-    };
-    let updates = GovUkChange::from_eml(&String::from_utf8(data)?).context("Parsing email")?;
-    let repo = Repository::open(repo_base).context("Opening repo")?;
-    let mut parent = Some(repo.find_reference(reference)?.peel_to_commit()?);
-    for change in &updates {
-        parent = Some(handle_change(change, &repo, parent).context(format!("Processing change {:?}", change))?);
-    }
-    // successfully handled, 'commit' the new commits by updating the reference and then move email to outbox
-    if let Some(commit) = parent {
-        let _ref = repo.reference(
-            reference,
-            commit.id(),
-            true,
-            &format!("Added updates from {:?}", dir_entry.path()),
-        )?;
+    let data = store.read(message).context("Reading message from store")?;
+
+    let message_path_str = message.path.to_str().unwrap_or_default();
+    if ledger.already_processed(&data).context("Checking ledger")? {
+        println!("{}: already processed according to the ledger, skipping", message_path_str);
+    } else {
+        let updates = GovUkChange::from_eml(std::str::from_utf8(&data)?).context("Parsing email")?;
+        let repo = Repository::open(&repo_base).context("Opening repo")?;
+        let mut parent = Some(repo.find_reference(reference)?.peel_to_commit()?);
+        for change in &updates {
+            parent = Some(
+                handle_change(change, &repo, parent, reference, ledger)
+                    .context(format!("Processing change {:?}", change))?,
+            );
+        }
+        // each handle_change call above already advanced `reference` (with a compare-and-swap
+        // retry) to its own commit; only once every change in the email has landed do we record
+        // it as received, so a crash partway through leaves it to be safely retried rather than
+        // skipped and archived unprocessed.
+        ledger
+            .record_received(&message.envelope_from, &message.to_dir_name, message_path_str, &data)
+            .context("Recording received email in ledger")?;
     }
-    let done_path = out_dir.as_ref().join(to_dir_name).join(dir_entry.file_name());
-    fs::create_dir_all(done_path.parent().unwrap()).context("Creating outbox dir")?;
-    fs::rename(dir_entry.path(), &done_path).context(format!(
-        "Renaming file {} to {}",
-        dir_entry.path().to_str().unwrap_or_default(),
-        &done_path.to_str().unwrap_or_default()
-    ))?;
-    Ok(())
+
+    store.mark_done(message)
 }
 
 fn handle_change<'repo>(
-    GovUkChange {
+    change @ GovUkChange {
         url,
-        change,
+        change: change_text,
         updated_at,
         category,
     }: &GovUkChange,
     repo: &'repo Repository,
     parent: Option<Commit<'repo>>,
+    reference: &str,
+    ledger: &Ledger,
 ) -> Result<Commit<'repo>> {
-    let mut commit_builder = CommitBuilder::new(&repo, parent)?;
+    let parent_tree = parent.as_ref().map(Commit::tree).transpose()?;
+
+    let concurrency = dotenv::var("FETCH_CONCURRENCY")
+        .ok()
+        .and_then(|c| c.parse().ok())
+        .unwrap_or(DEFAULT_CONCURRENCY);
+    let mut written = vec![];
+    fetch_change(url, concurrency, |path, bytes| {
+        written.push((path, bytes.to_vec()));
+        Ok(())
+    })?;
 
-    fetch_change(url, |path, bytes| {
-        // write the blob
+    // gov.uk resends the same notification email when it only touches metadata we don't keep
+    // (e.g. re-indexing); diff each written file against the parent commit's blob so such
+    // re-sends don't produce an empty commit, and fold what did change into the message.
+    let mut any_changed = parent_tree.is_none();
+    let mut diff_summaries = vec![];
+    for (path, bytes) in &written {
+        let relative_path: &Path = path.strip_prefix("/").unwrap_or(path.as_path());
+        let previous = parent_tree
+            .as_ref()
+            .and_then(|tree| tree.get_path(relative_path).ok())
+            .and_then(|entry| entry.to_object(repo).ok())
+            .and_then(|obj| obj.into_blob().ok());
+
+        match &previous {
+            Some(blob) if blob.content() == bytes.as_slice() => {}
+            Some(blob) => {
+                any_changed = true;
+                if let (Ok(previous_text), Ok(current_text)) = (std::str::from_utf8(blob.content()), std::str::from_utf8(bytes)) {
+                    if let Some(summary) = diff_summary(previous_text, current_text) {
+                        diff_summaries.push(format!("{}:\n{}", path.display(), summary));
+                    }
+                }
+            }
+            None => any_changed = true,
+        }
+    }
+
+    if !any_changed {
+        let parent = parent.expect("any_changed is only false when there was a parent commit");
+        println!("{}: re-sent with no changes since the last commit, skipping", url);
+        return Ok(parent);
+    }
+
+    let mut commit_builder = CommitBuilder::new(&repo, parent)?;
+    for (path, bytes) in &written {
         let oid = repo.blob(bytes)?;
-        commit_builder.add_to_tree(path.to_str().unwrap(), oid, 0o100644)
-    })?;
+        commit_builder.add_to_tree(path.to_str().unwrap(), oid, 0o100644)?;
+    }
 
-    let message = format!(
+    let mut message = format!(
         "{}: {}{}",
         updated_at,
-        change,
+        change_text,
         category.as_ref().map(|c| format!(" [{}]", c)).unwrap_or_default()
     );
+    if !diff_summaries.is_empty() {
+        message.push_str("\n\n");
+        message.push_str(&diff_summaries.join("\n\n"));
+    }
     let govuk_sig = Signature::now("Gov.uk", "info@gov.uk")?;
     let gitgov_sig = Signature::now("Gitgov", "gitgov@njk.onl")?;
-    Ok(commit_builder.commit(&govuk_sig, &gitgov_sig, &message)?)
+    let commit = commit_builder.commit_to_ref(reference, &govuk_sig, &gitgov_sig, &message)?;
+    ledger
+        .record_applied_change(url.as_str(), &change.change, updated_at, category.as_deref(), commit.id())
+        .context("Recording applied change in ledger")?;
+    Ok(commit)
 }
 
-fn fetch_change(url: &Url, mut write_out: impl FnMut(PathBuf, &[u8]) -> Result<()>) -> Result<()> {
-    let mut urls = VecDeque::new();
-    urls.push_back(url.to_owned());
+/// How many attachments/pages to fetch in parallel when no `FETCH_CONCURRENCY` is configured.
+const DEFAULT_CONCURRENCY: usize = 4;
+
+/// Crawls `url` and its attachments, fetching up to `concurrency` URLs in parallel. A
+/// visited-set stops the same URL being fetched twice (the graph isn't a tree - attachments
+/// can be shared between pages). Results are sorted by path before being handed to
+/// `write_out` so identical input yields an identical tree regardless of fetch order, keeping
+/// `write_out`'s blob insertion into the `CommitBuilder` tree single-threaded and deterministic.
+///
+/// Each fetch's retrying is left entirely to `retrieve_doc` (which already backs off and honors
+/// `Retry-After` on its own) rather than wrapped in a second `with_backoff` here too - stacking
+/// both meant a persistent failure replayed up to `DEFAULT_MAX_ATTEMPTS` squared worth of
+/// requests against two independent schedules.
+fn fetch_change(
+    url: &Url,
+    concurrency: usize,
+    mut write_out: impl FnMut(PathBuf, &[u8]) -> Result<()>,
+) -> Result<()> {
+    if url.host_str() != Some("www.gov.uk") {
+        println!("Ignoring link to offsite document : {}", url);
+        return Ok(());
+    }
 
-    while let Some(url) = urls.pop_front() {
-        if url.host_str() != Some("www.gov.uk") {
-            println!("Ignoring link to offsite document : {}", &url);
-            continue;
-        }
-        let doc = retrieve_doc(&url)?;
-        urls.extend(doc.content.attachments().unwrap_or_default().iter().cloned());
+    let mut visited = HashSet::new();
+    visited.insert(url.to_owned());
+    let mut frontier = vec![url.to_owned()];
+    let mut results: Vec<(PathBuf, Vec<u8>)> = vec![];
+
+    while !frontier.is_empty() {
+        let batch: Vec<Url> = frontier.drain(..frontier.len().min(concurrency.max(1))).collect();
+        let batch_results: Vec<Result<Doc>> = thread::scope(|scope| {
+            let handles: Vec<_> = batch
+                .iter()
+                .map(|url| {
+                    let url = url.clone();
+                    scope.spawn(move || retrieve_doc(url))
+                })
+                .collect();
+            handles.into_iter().map(|handle| handle.join().unwrap()).collect()
+        });
 
-        let mut path = PathBuf::from(doc.url.path());
-        if doc.content.is_html() {
-            assert!(path.set_extension("html"));
+        for doc in batch_results {
+            let doc = doc?;
+            for attachment in doc.content.attachments().unwrap_or_default() {
+                if attachment.host_str() != Some("www.gov.uk") {
+                    println!("Ignoring link to offsite document : {}", attachment);
+                } else if visited.insert(attachment.clone()) {
+                    frontier.push(attachment.clone());
+                }
+            }
+
+            let mut path = PathBuf::from(doc.url.path());
+            if doc.content.is_html() {
+                assert!(path.set_extension("html"));
+            }
+            results.push((path, doc.content.as_bytes().to_vec()));
         }
-        println!("Writing doc to : {}", path.to_str().unwrap());
-        write_out(path, doc.content.as_bytes())?
     }
 
+    results.sort_by(|(a, _), (b, _)| a.cmp(b));
+    for (path, bytes) in results {
+        println!("Writing doc to : {}", path.to_str().unwrap());
+        write_out(path, &bytes)?;
+    }
     Ok(())
 }
 
@@ -338,19 +718,26 @@ mod test {
     use crate::handle_change;
     use anyhow::Result;
     use git2::{Repository, Signature};
-    use gitgov_rs::{email_update::GovUkChange, git::CommitBuilder};
+    use gitgov_rs::{
+        email_update::GovUkChange,
+        git::CommitBuilder,
+        ledger::Ledger,
+        mail_store::{CustomLayoutStore, MailStore},
+    };
     use lettre::{ClientSecurity, SmtpClient, Transport};
     use lettre_email::EmailBuilder;
-    use std::{fs, net::TcpListener, path::Path};
+    use std::{fs, io::Write, net::TcpListener, path::Path, sync::Arc};
 
     #[test]
     fn test_receive_updates() {
         let _ = std::fs::remove_dir_all("tests/tmp/inbox");
         let socket = TcpListener::bind("localhost:0").unwrap();
         let addr = socket.local_addr().unwrap();
+        let store: Arc<dyn MailStore> =
+            Arc::new(CustomLayoutStore::new("tests/tmp/inbox", "tests/tmp/outbox"));
         std::thread::spawn(move || {
             let (stream, remote_addr) = socket.accept().unwrap();
-            receive_updates_on_socket(stream, remote_addr, "tests/tmp/inbox").unwrap();
+            receive_updates_on_socket(stream, remote_addr, store, None, None, None, false).unwrap();
         });
 
         let email = EmailBuilder::new()
@@ -373,6 +760,31 @@ mod test {
         );
     }
 
+    #[test]
+    fn test_pending_messages_round_trip() -> Result<()> {
+        // Exercises the full create_writer -> pending -> read -> mark_done cycle, rather than
+        // poking at the filesystem directly like test_receive_updates does - this is what would
+        // have caught the pending()/create_writer depth mismatch.
+        let _ = std::fs::remove_dir_all("tests/tmp/round_trip_inbox");
+        let _ = std::fs::remove_dir_all("tests/tmp/round_trip_outbox");
+        let store: Arc<dyn MailStore> =
+            Arc::new(CustomLayoutStore::new("tests/tmp/round_trip_inbox", "tests/tmp/round_trip_outbox"));
+
+        let mut writer = store.create_writer("test@gov.uk", &["brexit@example.org".to_owned()])?;
+        writer.write_all(b"From: test@gov.uk\r\n\r\nbody")?;
+        writer.finish()?;
+
+        let pending = store.pending()?;
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].to_dir_name, "brexit@example.org");
+        assert_eq!(pending[0].envelope_from, "test@gov.uk");
+        assert_eq!(store.read(&pending[0])?, b"From: test@gov.uk\r\n\r\nbody");
+
+        store.mark_done(&pending[0])?;
+        assert!(store.pending()?.is_empty());
+        Ok(())
+    }
+
     #[test]
     fn test_obtain_changes() -> Result<()> {
         const REPO_DIR: &str = "tests/tmp/test_obtain_changes";
@@ -383,6 +795,7 @@ mod test {
         // let oid = repo.treebuilder(None)?.write()?;
         // let tree = repo.find_tree(oid)?;
         // repo.commit(Some(GIT_REF), &test_sig, &test_sig, "initial commit", &tree, &[])?;
+        let ledger = Ledger::open(format!("{}/ledger.sqlite3", REPO_DIR))?;
         let commit = handle_change(
             &GovUkChange {
                 url: "https://www.gov.uk/government/consultations/bus-services-act-2017-bus-open-data".parse()?,
@@ -392,10 +805,15 @@ mod test {
             },
             &repo,
             None,
+            "refs/heads/main",
+            &ledger,
         )?;
-        repo.reference("refs/heads/main", commit.id(), false, "log_message")?;
 
         assert_eq!(commit.message(), Some("some time: testing the stuff [Test Category]"));
+        assert_eq!(
+            repo.find_reference("refs/heads/main")?.peel_to_commit()?.id(),
+            commit.id()
+        );
         assert_eq!(
             commit
                 .tree()?
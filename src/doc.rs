@@ -95,6 +95,27 @@ impl DocUpdate {
     }
 }
 
+/// Computes a human-readable summary of added/removed/changed text blocks between a
+/// previously-committed revision of a document and `current`, both already normalized by
+/// [`remove_ids`]. Returns `None` when they're byte-identical or the structural diff turns up
+/// nothing, so callers can skip committing a re-sent notification email that changed nothing.
+pub fn diff_summary(previous: &str, current: &str) -> Option<String> {
+    if previous == current {
+        return None;
+    }
+    let differences = html_diff::get_differences(previous, current);
+    if differences.is_empty() {
+        return None;
+    }
+    Some(
+        differences
+            .iter()
+            .map(|difference| format!("- {:?}", difference))
+            .collect::<Vec<_>>()
+            .join("\n"),
+    )
+}
+
 pub fn remove_ids(html: &str) -> Result<String> {
     rewrite_str(
         html,
@@ -0,0 +1,102 @@
+//! Sieve-style rules for deciding, before a message is written to disk, whether to keep it and
+//! which inbox subpath to file it under.
+//!
+//! Rules are evaluated once DATA has been fully received, since the subject line lives in the
+//! message body, not the envelope. The first rule whose conditions all match wins; if none
+//! match, the message is stored under the envelope recipient as before.
+
+use anyhow::{bail, Context, Result};
+use std::{fs, path::Path};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Action {
+    /// Drop the message before it's ever written to disk.
+    Discard,
+    /// File the message under this inbox subdirectory name instead of the recipient address.
+    RouteTo(String),
+}
+
+#[derive(Debug, Clone)]
+struct Rule {
+    from_contains: Option<String>,
+    to_contains: Option<String>,
+    subject_contains: Option<String>,
+    action: Action,
+}
+
+impl Rule {
+    fn matches(&self, message: &MessageInfo) -> bool {
+        self.from_contains.as_deref().map_or(true, |needle| message.from.contains(needle))
+            && self
+                .to_contains
+                .as_deref()
+                .map_or(true, |needle| message.to.iter().any(|to| to.contains(needle)))
+            && self
+                .subject_contains
+                .as_deref()
+                .map_or(true, |needle| message.subject.map_or(false, |subject| subject.contains(needle)))
+    }
+}
+
+/// Envelope/header facts a [`Rule`] can match on.
+pub struct MessageInfo<'a> {
+    pub from: &'a str,
+    pub to: &'a [String],
+    pub subject: Option<&'a str>,
+}
+
+/// An ordered list of [`Rule`]s loaded from a tab-separated `field\tsubstring\taction` file
+/// (blank lines and `#`-prefixed comments ignored). `field` is `from`, `to` or `subject`;
+/// `action` is `discard` or `route:<name>`.
+pub struct RuleSet(Vec<Rule>);
+
+impl RuleSet {
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self> {
+        let contents = fs::read_to_string(path.as_ref()).context("Reading filter rules file")?;
+        let mut rules = vec![];
+        for line in contents.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut columns = line.splitn(3, '\t');
+            let field = columns.next().context("Missing field column")?;
+            let needle = columns.next().context("Missing match column")?.to_owned();
+            let action = match columns.next().context("Missing action column")? {
+                "discard" => Action::Discard,
+                routed if routed.starts_with("route:") => Action::RouteTo(routed["route:".len()..].to_owned()),
+                other => bail!("Unknown filter action {:?}", other),
+            };
+            let mut rule = Rule {
+                from_contains: None,
+                to_contains: None,
+                subject_contains: None,
+                action,
+            };
+            match field {
+                "from" => rule.from_contains = Some(needle),
+                "to" => rule.to_contains = Some(needle),
+                "subject" => rule.subject_contains = Some(needle),
+                other => bail!("Unknown filter field {:?}", other),
+            }
+            rules.push(rule);
+        }
+        Ok(RuleSet(rules))
+    }
+
+    /// The first matching rule's action, or `None` if no rule matches.
+    pub fn evaluate(&self, message: &MessageInfo) -> Option<Action> {
+        self.0.iter().find(|rule| rule.matches(message)).map(|rule| rule.action.clone())
+    }
+}
+
+/// Pulls the (undecoded, unfolded) `Subject:` header out of a raw `.eml` buffer without a full
+/// MIME parse - good enough for rule matching, not for display.
+pub fn extract_subject(raw_message: &[u8]) -> Option<&str> {
+    let text = std::str::from_utf8(raw_message).ok()?;
+    let headers = text.split("\r\n\r\n").next().unwrap_or(text);
+    headers.lines().find_map(|line| {
+        let (name, value) = line.split_once(':')?;
+        name.trim().eq_ignore_ascii_case("Subject").then(|| value.trim())
+    })
+}
@@ -1,45 +1,51 @@
 //! Helpers for git
 
 use anyhow::{format_err, Context, Result};
-use git2::{Commit, Oid, Repository, Signature, Tree, TreeBuilder};
+use git2::{Commit, ErrorCode, Oid, Repository, Signature, Tree, TreeBuilder};
+
+/// How many times [`CommitBuilder::commit_to_ref`] will re-read the ref and retry before giving
+/// up, when it keeps losing the compare-and-swap race against another writer.
+const MAX_CAS_ATTEMPTS: u32 = 5;
 
 pub struct CommitBuilder<'repo> {
     repo: &'repo Repository,
-    tree_builder: TreeBuilder<'repo>,
     parent: Option<Commit<'repo>>,
+    /// Recorded rather than written straight into a `TreeBuilder`, so [`commit_to_ref`](Self::commit_to_ref)
+    /// can replay them onto a different parent tree if the ref it's targeting moves underneath it.
+    insertions: Vec<(String, Oid, i32)>,
 }
 
 impl<'repo> CommitBuilder<'repo> {
     /// Start building a commit on this repository
     pub fn new(repo: &'repo Repository, parent: Option<Commit<'repo>>) -> Result<Self, git2::Error> {
-        let tree: Option<Tree<'_>> = parent.as_ref().map(Commit::tree).transpose()?;
-        let tree_builder: TreeBuilder<'repo> = repo.treebuilder(tree.as_ref())?;
         Ok(CommitBuilder {
             repo,
-            tree_builder,
             parent,
+            insertions: Vec::new(),
         })
     }
 
     pub fn add_to_tree(&mut self, path: &str, oid: Oid, file_mode: i32) -> Result<()> {
-        write_to_path_in_tree(
-            self.repo,
-            &mut self.tree_builder,
-            path.strip_prefix('/').context("relative path provided")?,
-            oid,
-            file_mode,
-        )
+        let path = path.strip_prefix('/').context("relative path provided")?.to_owned();
+        self.insertions.push((path, oid, file_mode));
+        Ok(())
     }
 
-    /// Writes the built tree, a comit for it and updates the ref
-    pub fn commit(
-        self,
-        author: &Signature,
-        committer: &Signature,
-        message: &str,
-    ) -> Result<Commit<'repo>, git2::Error> {
-        let oid = self.tree_builder.write()?;
-        let tree = self.repo.find_tree(oid)?;
+    /// Builds a tree from `parent`'s tree plus the recorded insertions.
+    fn build_tree(&self, parent: Option<&Commit<'repo>>) -> Result<Tree<'repo>> {
+        let tree: Option<Tree<'_>> = parent.map(Commit::tree).transpose()?;
+        let mut tree_builder = self.repo.treebuilder(tree.as_ref())?;
+        for (path, oid, file_mode) in &self.insertions {
+            write_to_path_in_tree(self.repo, &mut tree_builder, path, *oid, *file_mode)?;
+        }
+        let oid = tree_builder.write()?;
+        Ok(self.repo.find_tree(oid)?)
+    }
+
+    /// Writes the built tree and a commit for it. Doesn't touch any ref - callers that want to
+    /// advance a branch to the result need [`commit_to_ref`](Self::commit_to_ref) instead.
+    pub fn commit(self, author: &Signature, committer: &Signature, message: &str) -> Result<Commit<'repo>> {
+        let tree = self.build_tree(self.parent.as_ref())?;
         let oid = self.repo.commit(
             None,
             author,
@@ -48,7 +54,50 @@ impl<'repo> CommitBuilder<'repo> {
             &tree,
             self.parent.as_ref().map(|c| vec![c]).unwrap_or_default().as_slice(),
         )?;
-        self.repo.find_commit(oid)
+        Ok(self.repo.find_commit(oid)?)
+    }
+
+    /// Like [`commit`](Self::commit), but builds on top of the *current* tip of `refname` -
+    /// re-read here rather than trusted from the `parent` given to [`new`](Self::new), which may
+    /// already be stale - and atomically advances `refname` to the result.
+    ///
+    /// Passing `refname` through to `Repository::commit` makes libgit2 check that `refname`
+    /// still points at the parent we built on before moving it, refusing the update (with
+    /// [`ErrorCode::Modified`]) if another writer already advanced it first - a git-native
+    /// compare-and-swap. On that race this re-reads the new tip, replays the recorded tree
+    /// insertions on top of it, and retries, up to [`MAX_CAS_ATTEMPTS`] times, so several SMTP
+    /// connections committing docs concurrently land as a correct append-only history instead of
+    /// one silently clobbering another.
+    pub fn commit_to_ref(
+        mut self,
+        refname: &str,
+        author: &Signature,
+        committer: &Signature,
+        message: &str,
+    ) -> Result<Commit<'repo>> {
+        for attempt in 1..=MAX_CAS_ATTEMPTS {
+            self.parent = match self.repo.find_reference(refname) {
+                Ok(reference) => Some(reference.peel_to_commit()?),
+                Err(err) if err.code() == ErrorCode::NotFound => None,
+                Err(err) => return Err(err.into()),
+            };
+            let tree = self.build_tree(self.parent.as_ref())?;
+            let parents = self.parent.as_ref().map(|c| vec![c]).unwrap_or_default();
+            match self
+                .repo
+                .commit(Some(refname), author, committer, message, &tree, &parents)
+            {
+                Ok(oid) => return Ok(self.repo.find_commit(oid)?),
+                Err(err) if err.code() == ErrorCode::Modified && attempt < MAX_CAS_ATTEMPTS => {
+                    println!(
+                        "{}: ref moved while committing (attempt {}/{}), rebuilding on the new tip",
+                        refname, attempt, MAX_CAS_ATTEMPTS
+                    );
+                }
+                Err(err) => return Err(err.into()),
+            }
+        }
+        unreachable!("loop always returns by the last attempt")
     }
 }
 
@@ -0,0 +1,111 @@
+//! Alternative ways to get raw `.eml` messages into a [`MailStore`], besides listening for
+//! inbound SMTP connections on port 25.
+
+use crate::mail_store::MailStore;
+use anyhow::{Context, Result};
+use imap::Session;
+use native_tls::TlsStream;
+use std::{io::Write, net::TcpStream, sync::Arc};
+
+/// A source of incoming mail that deposits raw RFC822 messages into a [`MailStore`].
+/// `process_updates_in_dir` reads from that store and doesn't need to know which
+/// `IngestSource` put a message there.
+pub trait IngestSource {
+    /// Pull whatever mail is currently available (or, for a listening source, handle one
+    /// connection) and write each message into the store via [`MailStore::create_writer`].
+    fn ingest(&mut self) -> Result<()>;
+}
+
+/// Polls an IMAP mailbox for unseen messages, for deployments where owning the MX record
+/// for a domain isn't practical.
+pub struct ImapSource {
+    host: String,
+    port: u16,
+    username: String,
+    password: String,
+    folder: String,
+    store: Arc<dyn MailStore>,
+}
+
+impl ImapSource {
+    pub fn from_env(store: Arc<dyn MailStore>) -> Result<Self> {
+        Ok(ImapSource {
+            host: dotenv::var("IMAP_HOST").context("IMAP_HOST not set")?,
+            port: dotenv::var("IMAP_PORT")
+                .ok()
+                .and_then(|port| port.parse().ok())
+                .unwrap_or(993),
+            username: dotenv::var("IMAP_USER").context("IMAP_USER not set")?,
+            password: dotenv::var("IMAP_PASSWORD").context("IMAP_PASSWORD not set")?,
+            folder: dotenv::var("IMAP_FOLDER").unwrap_or_else(|_| "INBOX".to_owned()),
+            store,
+        })
+    }
+
+    fn connect(&self) -> Result<Session<TlsStream<TcpStream>>> {
+        let tls = native_tls::TlsConnector::builder().build().context("Building TLS connector")?;
+        let client = imap::connect((self.host.as_str(), self.port), &self.host, &tls)
+            .context("Connecting to IMAP host")?;
+        client
+            .login(&self.username, &self.password)
+            .map_err(|(err, _client)| err)
+            .context("IMAP login failed")
+    }
+}
+
+impl IngestSource for ImapSource {
+    fn ingest(&mut self) -> Result<()> {
+        let mut session = self.connect()?;
+        session.select(&self.folder).context("Selecting IMAP folder")?;
+
+        let mut uids: Vec<u32> = session
+            .uid_search("UNSEEN")
+            .context("Searching for unseen messages")?
+            .into_iter()
+            .collect();
+        uids.sort_unstable();
+
+        for uid in uids {
+            let fetched = session
+                // BODY.PEEK[] rather than RFC822, which would set \Seen as a side effect of the fetch
+                .uid_fetch(uid.to_string(), "(BODY.PEEK[] ENVELOPE)")
+                .context("Fetching message")?;
+            let message = match fetched.iter().next() {
+                Some(message) => message,
+                None => continue,
+            };
+            let body = message.body().context("Message had no RFC822 body")?;
+            let envelope = message.envelope().context("Message had no envelope")?;
+            let from = envelope
+                .from
+                .as_ref()
+                .and_then(|addrs| addrs.first())
+                .map(address_to_string)
+                .unwrap_or_else(|| "unknown".to_owned());
+            let to: Vec<String> = envelope
+                .to
+                .as_ref()
+                .map(|addrs| addrs.iter().map(address_to_string).collect())
+                .unwrap_or_default();
+
+            let mut writer = self.store.create_writer(&from, &to)?;
+            writer.write_all(body).context("Writing message to store")?;
+            writer.finish()?;
+
+            // only mark the message handled once it's safely on disk
+            session
+                .uid_store(uid.to_string(), "+FLAGS (\\Seen)")
+                .context("Marking message seen")?;
+        }
+        Ok(())
+    }
+}
+
+fn address_to_string(address: &imap_proto::types::Address) -> String {
+    let mailbox = address.mailbox.as_ref().map(|m| String::from_utf8_lossy(m));
+    let host = address.host.as_ref().map(|h| String::from_utf8_lossy(h));
+    match (mailbox, host) {
+        (Some(mailbox), Some(host)) => format!("{}@{}", mailbox, host),
+        _ => "unknown".to_owned(),
+    }
+}
@@ -0,0 +1,107 @@
+//! A small SQLite-backed ledger of every email ingested and every change committed from it.
+//!
+//! Dedup and "have I handled this?" state used to live entirely in the inbox/outbox file
+//! moves, so a crash between updating the git ref and renaming the file could reprocess an
+//! email. [`Ledger::already_processed`] / [`Ledger::record_received`] split the check from the
+//! recording so the body hash is only ever written once every change the email implied has
+//! actually committed - recording it any earlier would let a crash mid-processing get silently
+//! archived by `mark_done` next run without its commits ever having been made. This also gives
+//! operators a queryable history of every document commit keyed by source URL.
+
+use anyhow::{Context, Result};
+use chrono::Utc;
+use git2::Oid;
+use rusqlite::{params, Connection, OptionalExtension};
+use sha2::{Digest, Sha256};
+use std::path::Path;
+
+pub struct Ledger {
+    conn: Connection,
+}
+
+impl Ledger {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let conn = Connection::open(path).context("Opening ledger database")?;
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS received_emails (
+                id            INTEGER PRIMARY KEY,
+                envelope_from TEXT NOT NULL,
+                envelope_to   TEXT NOT NULL,
+                received_at   TEXT NOT NULL,
+                body_sha256   TEXT NOT NULL UNIQUE,
+                inbox_path    TEXT NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS applied_changes (
+                id          INTEGER PRIMARY KEY,
+                url         TEXT NOT NULL,
+                change      TEXT NOT NULL,
+                updated_at  TEXT NOT NULL,
+                category    TEXT,
+                commit_oid  TEXT NOT NULL
+            );",
+        )
+        .context("Creating ledger tables")?;
+        Ok(Ledger { conn })
+    }
+
+    /// Returns `true` if this exact email body has already been recorded, in which case it
+    /// must not be processed again.
+    ///
+    /// This only checks - it doesn't record. Callers must call [`record_received`](Self::record_received)
+    /// themselves once the email has actually been handled (every change committed), not before:
+    /// recording it up front would mark a half-processed email (one that errored partway through
+    /// `handle_change`) as done, so a retry after a crash would skip it - and `mark_done` would
+    /// then archive it - without the commits it implied ever having been made.
+    pub fn already_processed(&self, body: &[u8]) -> Result<bool> {
+        let hash = Self::hash(body);
+        self.conn
+            .query_row(
+                "SELECT 1 FROM received_emails WHERE body_sha256 = ?1",
+                params![hash],
+                |_row| Ok(()),
+            )
+            .optional()
+            .context("Checking ledger for existing email")
+            .map(|row| row.is_some())
+    }
+
+    /// Records that this email body was received from `envelope_from` to `envelope_to` (the
+    /// SMTP `MAIL FROM`/`RCPT TO` addresses, not whatever the `From:`/`To:` headers say) and
+    /// landed at `inbox_path`. Call once every change it contained has committed successfully,
+    /// so reprocessing after a crash mid-way is a safe no-op rather than a skip.
+    pub fn record_received(&self, envelope_from: &str, envelope_to: &str, inbox_path: &str, body: &[u8]) -> Result<()> {
+        let hash = Self::hash(body);
+        self.conn
+            .execute(
+                "INSERT OR IGNORE INTO received_emails (envelope_from, envelope_to, received_at, body_sha256, inbox_path)
+                 VALUES (?1, ?2, ?3, ?4, ?5)",
+                params![envelope_from, envelope_to, Utc::now().to_rfc3339(), hash, inbox_path],
+            )
+            .context("Recording received email")?;
+        Ok(())
+    }
+
+    fn hash(body: &[u8]) -> String {
+        format!("{:x}", Sha256::digest(body))
+    }
+
+    /// Records that `url` was committed as `commit_oid`, for audit and so operators can find
+    /// the commit that applied a given GOV.UK change.
+    pub fn record_applied_change(
+        &self,
+        url: &str,
+        change: &str,
+        updated_at: &str,
+        category: Option<&str>,
+        commit_oid: Oid,
+    ) -> Result<()> {
+        self.conn
+            .execute(
+                "INSERT INTO applied_changes (url, change, updated_at, category, commit_oid)
+                 VALUES (?1, ?2, ?3, ?4, ?5)",
+                params![url, change, updated_at, category, commit_oid.to_string()],
+            )
+            .context("Recording applied change")?;
+        Ok(())
+    }
+}
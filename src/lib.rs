@@ -1,29 +1,72 @@
 use anyhow::{bail, format_err, Context, Result};
-use std::io::copy;
+use retry::{jitter, DEFAULT_MAX_ATTEMPTS};
+use std::{io::copy, thread, time::Duration};
 use ureq::get;
 use url::Url;
 
+pub mod auth;
 pub mod doc;
 pub mod email_update;
 pub use doc::{Doc, DocContent};
+pub mod filter;
 pub mod git;
+pub mod ingest;
+pub mod ledger;
+pub mod mail_store;
+pub mod retry;
 
+const BASE_DELAY: Duration = Duration::from_millis(500);
+const MAX_DELAY: Duration = Duration::from_secs(60);
+
+/// Retries connection errors and 5xx/429 responses with exponential backoff (base 500ms,
+/// doubling, capped at 60s, with jitter), honoring a `Retry-After` header when the server sends
+/// one, up to [`DEFAULT_MAX_ATTEMPTS`]. Everything else (a 404, a parse error) is returned
+/// immediately since retrying wouldn't help.
 pub fn retrieve_doc(url: Url) -> Result<Doc> {
     // TODO return the doc and the urls of attachments, probably remove async, I can just use a thread pool and worker queue
     println!("retrieving url : {}", &url);
-    let response = get(&url.as_str()).call();
-    if let Some(_err) = response.synthetic_error() {
-        bail!("Error retrieving");
+    let mut delay = BASE_DELAY;
+    for attempt in 1..=DEFAULT_MAX_ATTEMPTS {
+        let response = get(url.as_str()).call();
+        let status = response.status();
+        let is_transient = response.synthetic_error().is_some() || status == 429 || status >= 500;
+
+        if !is_transient {
+            return parse_response(url, response);
+        }
+
+        let wait = retry_after(&response).unwrap_or(delay);
+        if attempt == DEFAULT_MAX_ATTEMPTS {
+            bail!(
+                "Gave up retrieving {} after {} attempts (status {})",
+                url,
+                DEFAULT_MAX_ATTEMPTS,
+                status
+            );
+        }
+        println!(
+            "{}: transient failure (status {}) on attempt {}/{}, retrying in {:?}",
+            url, status, attempt, DEFAULT_MAX_ATTEMPTS, wait
+        );
+        thread::sleep(jitter(wait));
+        delay = (delay * 2).min(MAX_DELAY);
     }
+    unreachable!("loop always returns on the last attempt")
+}
 
+/// Parses a `Retry-After` header expressed as a number of seconds (the HTTP-date form isn't
+/// handled, gov.uk has only ever been observed sending the delta-seconds form).
+fn retry_after(response: &ureq::Response) -> Option<Duration> {
+    response.header("Retry-After")?.trim().parse::<u64>().ok().map(Duration::from_secs)
+}
+
+fn parse_response(url: Url, response: ureq::Response) -> Result<Doc> {
     if response.content_type() == "text/html" {
         let content = response.into_string().with_context(|| url.clone())?;
-        let doc = Doc {
+        Ok(Doc {
             content: DocContent::html(&content, Some(&url))?,
             url,
-        };
-
-        Ok(doc)
+        })
     } else {
         let mut reader = response.into_reader();
         let mut buf = vec![];
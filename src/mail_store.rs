@@ -0,0 +1,364 @@
+//! Pluggable storage for messages between being received and being processed.
+//!
+//! `EmailWrite`/`inbox_path_for_email` used to hardcode a bespoke
+//! `inbox/{from}/{to}/{datetime}.eml` layout, which isn't interoperable with standard mail
+//! tooling. [`MailStore`] abstracts that away so an operator can point the dumper at an
+//! existing Maildir that other tools also read, instead of only the custom layout.
+//!
+//! [`MemFdStagingStore`] wraps any other `MailStore` to keep the message body off disk
+//! entirely until it's known to be worth keeping.
+
+use crate::email_update::GovUkChange;
+use anyhow::{Context, Result};
+use chrono::{SecondsFormat, Utc};
+use file_lock::FileLock;
+use memfd::{Memfd, MemfdOptions};
+use std::{
+    fs,
+    io::{Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    process,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+/// A message that has been written to the store but not yet processed.
+pub struct PendingMessage {
+    /// Opaque grouping the store uses to file the message away on [`MailStore::mark_done`];
+    /// the custom layout uses it as the `to` subdirectory, Maildir ignores it.
+    pub to_dir_name: String,
+    /// The SMTP `MAIL FROM` address captured at `create_writer` time, for audit trails (the
+    /// [`Ledger`](crate::ledger::Ledger) wants the envelope address, not the `From:` header).
+    /// Empty if the store can't recover it once the message is pending (Maildir has nowhere to
+    /// stash it without breaking interop with other Maildir-reading tools).
+    pub envelope_from: String,
+    pub path: PathBuf,
+}
+
+/// A writer for a single incoming message's raw bytes. For [`MaildirStore`] and
+/// [`MemFdStagingStore`], dropping it without calling [`finish`](MessageWriter::finish) leaves
+/// no trace in [`MailStore::pending`] - only a successful `finish` publishes the message.
+/// [`CustomLayoutStore`] writes straight to the message's final `.eml` path, so a writer dropped
+/// there leaves the partial message in `pending`.
+pub trait MessageWriter: Write {
+    fn finish(self: Box<Self>) -> Result<()>;
+}
+
+/// Where incoming messages land and how the processing loop iterates them, independent of
+/// how they arrived (SMTP, IMAP, ...).
+pub trait MailStore: Send + Sync {
+    fn create_writer(&self, from: &str, to: &[String]) -> Result<Box<dyn MessageWriter>>;
+
+    /// Messages that have arrived but not yet been handled.
+    fn pending(&self) -> Result<Vec<PendingMessage>>;
+
+    fn read(&self, message: &PendingMessage) -> Result<Vec<u8>>;
+
+    /// Move a successfully-processed message out of the pending set.
+    fn mark_done(&self, message: &PendingMessage) -> Result<()>;
+}
+
+/// The original `inbox/{from-domain}/{to}/{datetime}.eml` layout, archiving to a parallel
+/// directory tree on success.
+pub struct CustomLayoutStore {
+    inbox: PathBuf,
+    archive: PathBuf,
+}
+
+impl CustomLayoutStore {
+    pub fn new(inbox: impl Into<PathBuf>, archive: impl Into<PathBuf>) -> Self {
+        CustomLayoutStore {
+            inbox: inbox.into(),
+            archive: archive.into(),
+        }
+    }
+}
+
+impl MailStore for CustomLayoutStore {
+    fn create_writer(&self, from: &str, to: &[String]) -> Result<Box<dyn MessageWriter>> {
+        let from_domain = from.split('@').nth(1);
+        let path = self
+            .inbox
+            .join(from_domain.unwrap_or(from))
+            .join(to.join(","))
+            .join(Utc::now().to_rfc3339_opts(SecondsFormat::AutoSi, true))
+            .with_extension("eml");
+        fs::create_dir_all(path.parent().unwrap())?;
+        let lock = FileLock::lock(path.to_str().context("non-utf8 inbox path")?, true, true)?;
+        Ok(Box::new(CustomLayoutWriter {
+            path,
+            lock,
+            from: from.to_owned(),
+        }))
+    }
+
+    fn pending(&self) -> Result<Vec<PendingMessage>> {
+        let mut pending = vec![];
+        for from_domain in fs::read_dir(&self.inbox)? {
+            let from_domain = from_domain?;
+            if !from_domain.metadata()?.is_dir() {
+                continue;
+            }
+            for to_inbox in fs::read_dir(from_domain.path())? {
+                let to_inbox = to_inbox?;
+                if !to_inbox.metadata()?.is_dir() {
+                    continue;
+                }
+                let to_dir_name = to_inbox.file_name().to_string_lossy().into_owned();
+                for email in fs::read_dir(to_inbox.path())? {
+                    let path = email?.path();
+                    if path.extension().is_some_and(|ext| ext == "from") {
+                        continue;
+                    }
+                    let envelope_from = fs::read_to_string(path.with_extension("from")).unwrap_or_default();
+                    pending.push(PendingMessage {
+                        to_dir_name: to_dir_name.clone(),
+                        envelope_from,
+                        path,
+                    });
+                }
+            }
+        }
+        Ok(pending)
+    }
+
+    fn read(&self, message: &PendingMessage) -> Result<Vec<u8>> {
+        fs::read(&message.path).context("Reading message")
+    }
+
+    fn mark_done(&self, message: &PendingMessage) -> Result<()> {
+        let file_name = message.path.file_name().context("message path had no file name")?;
+        let done_path = self.archive.join(&message.to_dir_name).join(file_name);
+        fs::create_dir_all(done_path.parent().unwrap())?;
+        fs::rename(&message.path, &done_path).context(format!(
+            "Renaming file {} to {}",
+            message.path.to_str().unwrap_or_default(),
+            done_path.to_str().unwrap_or_default()
+        ))?;
+        let from_sidecar = message.path.with_extension("from");
+        if from_sidecar.exists() {
+            fs::rename(&from_sidecar, done_path.with_extension("from")).context("Moving envelope-from sidecar")?;
+        }
+        Ok(())
+    }
+}
+
+struct CustomLayoutWriter {
+    path: PathBuf,
+    lock: FileLock,
+    from: String,
+}
+
+impl Write for CustomLayoutWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.lock.file.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.lock.file.flush()
+    }
+}
+
+impl MessageWriter for CustomLayoutWriter {
+    fn finish(mut self: Box<Self>) -> Result<()> {
+        self.flush()?;
+        // Stashed alongside the message so `pending()` can recover the envelope sender for the
+        // ledger even after a restart, without changing the on-disk message itself.
+        fs::write(self.path.with_extension("from"), &self.from).context("Writing envelope-from sidecar")?;
+        Ok(())
+    }
+}
+
+/// A standard Maildir (`tmp/`, `new/`, `cur/`) under `root`, per the Maildir convention:
+/// messages are written into `tmp/`, atomically renamed into `new/` once complete, and moved
+/// to the `archive` directory given to [`new`](Self::new) once processed. `cur/` is created
+/// but never written to.
+pub struct MaildirStore {
+    root: PathBuf,
+    archive: PathBuf,
+}
+
+static UNIQUE_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+impl MaildirStore {
+    pub fn new(root: impl Into<PathBuf>, archive: impl Into<PathBuf>) -> Result<Self> {
+        let root = root.into();
+        for sub in ["tmp", "new", "cur"] {
+            fs::create_dir_all(root.join(sub))?;
+        }
+        Ok(MaildirStore {
+            root,
+            archive: archive.into(),
+        })
+    }
+
+    /// `<timestamp>.<counter>_<pid>.<hostname>`, the de-facto unique-filename convention.
+    fn unique_name() -> String {
+        let hostname = gethostname::gethostname().to_string_lossy().into_owned();
+        format!(
+            "{}.{}_{}.{}",
+            Utc::now().timestamp(),
+            UNIQUE_COUNTER.fetch_add(1, Ordering::Relaxed),
+            process::id(),
+            hostname
+        )
+    }
+}
+
+impl MailStore for MaildirStore {
+    fn create_writer(&self, _from: &str, _to: &[String]) -> Result<Box<dyn MessageWriter>> {
+        let name = Self::unique_name();
+        let tmp_path = self.root.join("tmp").join(&name);
+        let new_path = self.root.join("new").join(&name);
+        let file = fs::File::create(&tmp_path).context("Creating maildir tmp file")?;
+        Ok(Box::new(MaildirWriter {
+            tmp_path,
+            new_path,
+            file,
+        }))
+    }
+
+    fn pending(&self) -> Result<Vec<PendingMessage>> {
+        let mut pending = vec![];
+        for entry in fs::read_dir(self.root.join("new"))? {
+            pending.push(PendingMessage {
+                to_dir_name: String::new(),
+                // Maildir has no standard place to stash the envelope sender without breaking
+                // interop with other tools reading the same maildir, so this is left blank.
+                envelope_from: String::new(),
+                path: entry?.path(),
+            });
+        }
+        Ok(pending)
+    }
+
+    fn read(&self, message: &PendingMessage) -> Result<Vec<u8>> {
+        fs::read(&message.path).context("Reading message")
+    }
+
+    fn mark_done(&self, message: &PendingMessage) -> Result<()> {
+        let file_name = message.path.file_name().context("message path had no file name")?;
+        let done_path = self.archive.join(file_name);
+        fs::create_dir_all(&self.archive)?;
+        fs::rename(&message.path, &done_path).context(format!(
+            "Moving {} to {}",
+            message.path.to_str().unwrap_or_default(),
+            done_path.to_str().unwrap_or_default()
+        ))
+    }
+}
+
+struct MaildirWriter {
+    tmp_path: PathBuf,
+    new_path: PathBuf,
+    file: fs::File,
+}
+
+impl Write for MaildirWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.file.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl MessageWriter for MaildirWriter {
+    fn finish(mut self: Box<Self>) -> Result<()> {
+        self.flush()?;
+        drop(self.file);
+        // atomically publish by moving out of tmp/ once the message is fully written
+        fs::rename(&self.tmp_path, &self.new_path).context("Publishing message into maildir new/")
+    }
+}
+
+/// Wraps another [`MailStore`] so the in-flight message body is buffered in an anonymous,
+/// memory-backed `memfd` during DATA instead of a file under the real inbox. Nothing touches
+/// the wrapped store until [`MessageWriter::finish`] re-parses the buffered bytes and finds a
+/// wanted [`GovUkChange`] - a session that aborts mid-DATA, or a notification email with no
+/// actionable change, never reaches disk at all. This is the memfd temp-file technique the
+/// `meli` mail client uses for the same reason; the memfd here is not sealed read-only.
+pub struct MemFdStagingStore {
+    inner: Arc<dyn MailStore>,
+}
+
+impl MemFdStagingStore {
+    pub fn new(inner: Arc<dyn MailStore>) -> Self {
+        MemFdStagingStore { inner }
+    }
+}
+
+impl MailStore for MemFdStagingStore {
+    fn create_writer(&self, from: &str, to: &[String]) -> Result<Box<dyn MessageWriter>> {
+        let memfd = MemfdOptions::new()
+            .close_on_exec(true)
+            .create("incoming-message")
+            .context("Creating memfd for incoming message")?;
+        Ok(Box::new(MemFdWriter {
+            memfd,
+            inner: self.inner.clone(),
+            from: from.to_owned(),
+            to: to.to_vec(),
+        }))
+    }
+
+    fn pending(&self) -> Result<Vec<PendingMessage>> {
+        self.inner.pending()
+    }
+
+    fn read(&self, message: &PendingMessage) -> Result<Vec<u8>> {
+        self.inner.read(message)
+    }
+
+    fn mark_done(&self, message: &PendingMessage) -> Result<()> {
+        self.inner.mark_done(message)
+    }
+}
+
+struct MemFdWriter {
+    memfd: Memfd,
+    inner: Arc<dyn MailStore>,
+    from: String,
+    to: Vec<String>,
+}
+
+impl Write for MemFdWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.memfd.as_file().write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.memfd.as_file().flush()
+    }
+}
+
+impl MessageWriter for MemFdWriter {
+    fn finish(self: Box<Self>) -> Result<()> {
+        let mut file = self.memfd.into_file();
+        file.flush()?;
+        file.seek(SeekFrom::Start(0)).context("Rewinding memfd")?;
+        let mut data = Vec::new();
+        file.read_to_end(&mut data).context("Reading memfd back")?;
+
+        let wanted = match std::str::from_utf8(&data) {
+            Ok(eml) => match GovUkChange::from_eml(eml) {
+                Ok(updates) => !updates.is_empty(),
+                Err(err) => {
+                    println!("Discarding un-parseable message rather than spooling it to disk : {}", err);
+                    false
+                }
+            },
+            Err(_) => false,
+        };
+        if !wanted {
+            return Ok(());
+        }
+
+        let mut writer = self.inner.create_writer(&self.from, &self.to)?;
+        writer.write_all(&data)?;
+        writer.finish()
+    }
+}
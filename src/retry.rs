@@ -0,0 +1,78 @@
+//! Retry helpers for operations against the outside world (HTTP fetches, git pushes).
+//!
+//! Distinguishes failures worth retrying (a dropped connection, a timeout, a 5xx) from
+//! permanent ones (a 404, a parse error) that should be surfaced to the caller immediately
+//! instead of being retried to no effect.
+
+use anyhow::Error;
+use rand::Rng;
+use std::{
+    sync::atomic::{AtomicBool, Ordering},
+    thread,
+    time::Duration,
+};
+
+const BASE_DELAY: Duration = Duration::from_secs(1);
+const MAX_DELAY: Duration = Duration::from_secs(60);
+pub const DEFAULT_MAX_ATTEMPTS: u32 = 8;
+
+/// Whether an operation's failure is transient (worth retrying) or permanent (won't succeed
+/// no matter how many times it's retried).
+pub enum Failure {
+    Transient(Error),
+    Permanent(Error),
+}
+
+/// A coarse "can we currently reach the outside world" flag, updated by [`with_backoff`] and
+/// read by the main loop so that while offline it backs off instead of spinning through
+/// `socket.accept`/`push` immediately. Reset to online on the first success.
+#[derive(Default)]
+pub struct IsOnline(AtomicBool);
+
+impl IsOnline {
+    pub fn new() -> Self {
+        IsOnline(AtomicBool::new(true))
+    }
+
+    pub fn get(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    fn set(&self, online: bool) {
+        self.0.store(online, Ordering::Relaxed);
+    }
+}
+
+/// Retries `op` with exponential backoff (starting at 1s, doubling each attempt, capped at
+/// 60s, with a little jitter) as long as it reports [`Failure::Transient`], giving up after
+/// `max_attempts`. Updates `online` so other callers can observe the coarse connectivity state.
+pub fn with_backoff<T>(online: &IsOnline, max_attempts: u32, mut op: impl FnMut() -> Result<T, Failure>) -> Result<T, Error> {
+    let mut delay = BASE_DELAY;
+    for attempt in 1..=max_attempts {
+        match op() {
+            Ok(value) => {
+                online.set(true);
+                return Ok(value);
+            }
+            Err(Failure::Permanent(err)) => return Err(err),
+            Err(Failure::Transient(err)) => {
+                online.set(false);
+                if attempt == max_attempts {
+                    return Err(err.context(format!("Gave up after {} attempts", max_attempts)));
+                }
+                println!(
+                    "Transient error on attempt {}/{}, retrying in {:?} : {}",
+                    attempt, max_attempts, delay, err
+                );
+                thread::sleep(jitter(delay));
+                delay = (delay * 2).min(MAX_DELAY);
+            }
+        }
+    }
+    unreachable!("loop always returns on the last attempt")
+}
+
+pub(crate) fn jitter(delay: Duration) -> Duration {
+    let max_jitter_ms = (delay.as_millis() as u64 / 10).max(1);
+    delay + Duration::from_millis(rand::thread_rng().gen_range(0..max_jitter_ms))
+}